@@ -0,0 +1,47 @@
+use std::io;
+
+/// 現在のスレッドを、使用できるコアの `index` 番目 (コア数で折り返す) に固定する
+/// 固定したコアの番号を返す
+#[cfg(target_os = "linux")]
+pub fn pin_current_thread(index: usize) -> io::Result<usize> {
+    let cores = allowed_cores()?;
+    if cores.is_empty() {
+        return Err(io::Error::new(
+            io::ErrorKind::NotFound,
+            "no cores available",
+        ));
+    }
+    let core = cores[index % cores.len()];
+    // SAFETY: cpu_set_t はゼロ初期化で空の集合となり、CPU_SET は CPU_SETSIZE 未満のコアのみ設定する
+    unsafe {
+        let mut set: libc::cpu_set_t = std::mem::zeroed();
+        libc::CPU_SET(core, &mut set);
+        if libc::sched_setaffinity(0, std::mem::size_of::<libc::cpu_set_t>(), &set) != 0 {
+            return Err(io::Error::last_os_error());
+        }
+    }
+    Ok(core)
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn pin_current_thread(_index: usize) -> io::Result<usize> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        "pinning threads to cores is not supported on this platform",
+    ))
+}
+
+/// cgroupやtasksetで制限されている場合があるため、このプロセスが使用できるコアを取得する
+#[cfg(target_os = "linux")]
+fn allowed_cores() -> io::Result<Vec<usize>> {
+    // SAFETY: sched_getaffinity は渡したサイズの範囲内のみ書き込む
+    unsafe {
+        let mut set: libc::cpu_set_t = std::mem::zeroed();
+        if libc::sched_getaffinity(0, std::mem::size_of::<libc::cpu_set_t>(), &mut set) != 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok((0..libc::CPU_SETSIZE as usize)
+            .filter(|core| libc::CPU_ISSET(*core, &set))
+            .collect())
+    }
+}
@@ -0,0 +1,101 @@
+/// クライアントが最初に送信する認証メッセージ「トークン + 改行」
+pub fn message(token: &str) -> Vec<u8> {
+    let mut message = token.as_bytes().to_vec();
+    message.push(b'\n');
+    message
+}
+
+/// 認証メッセージの確認結果
+pub enum Verdict<'a> {
+    /// 認証メッセージが一致した、残りのデータを持つ
+    Accepted(&'a [u8]),
+    /// 改行をまだ受信していない
+    Incomplete,
+    Rejected,
+}
+
+/// 受信データの先頭の認証メッセージを確認する
+/// 改行はトークンの長さまでしか待たず、それを超えた場合は拒否する
+pub fn check_message<'a>(token: &str, received_data: &'a [u8]) -> Verdict<'a> {
+    let limit = received_data.len().min(token.len() + 1);
+    let newline = received_data[..limit]
+        .iter()
+        .position(|byte| *byte == b'\n');
+    match newline {
+        Some(end) if constant_time_eq(&received_data[..end], token.as_bytes()) => {
+            Verdict::Accepted(&received_data[end + 1..])
+        }
+        Some(_) => Verdict::Rejected,
+        None if received_data.len() > token.len() => Verdict::Rejected,
+        None => Verdict::Incomplete,
+    }
+}
+
+/// 受信データの先頭が認証メッセージであれば、残りのデータを返す
+pub fn strip_message<'a>(token: &str, received_data: &'a [u8]) -> Option<&'a [u8]> {
+    match check_message(token, received_data) {
+        Verdict::Accepted(rest) => Some(rest),
+        Verdict::Incomplete | Verdict::Rejected => None,
+    }
+}
+
+/// 一致しないバイトの位置によって比較時間が変わらないように、全てのバイトを比較する
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    if a.len() != b.len() {
+        return false;
+    }
+    a.iter().zip(b).fold(0, |diff, (x, y)| diff | (x ^ y)) == 0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn strips_message_and_returns_rest() {
+        assert_eq!(
+            strip_message("s3cret", b"s3cret\nhello"),
+            Some(&b"hello"[..])
+        );
+        assert_eq!(strip_message("s3cret", b"s3cret\n"), Some(&b""[..]));
+        assert_eq!(strip_message("s3cret", &message("s3cret")), Some(&b""[..]));
+    }
+
+    #[test]
+    fn does_not_strip_wrong_or_incomplete_message() {
+        assert_eq!(strip_message("s3cret", b"s3cXet\nhello"), None);
+        assert_eq!(strip_message("s3cret", b"s3cret"), None);
+        assert_eq!(strip_message("s3cret", b"s3c\n"), None);
+        assert_eq!(strip_message("s3cret", b"s3crets\n"), None);
+        assert_eq!(strip_message("s3cret", b""), None);
+    }
+
+    #[test]
+    fn waits_for_newline_up_to_token_length() {
+        assert!(matches!(check_message("s3cret", b""), Verdict::Incomplete));
+        assert!(matches!(
+            check_message("s3cret", b"s3c"),
+            Verdict::Incomplete
+        ));
+        assert!(matches!(
+            check_message("s3cret", b"s3cret"),
+            Verdict::Incomplete
+        ));
+        assert!(matches!(
+            check_message("s3cret", b"s3cret!"),
+            Verdict::Rejected
+        ));
+        assert!(matches!(
+            check_message("s3cret", &[b'x'; 100]),
+            Verdict::Rejected
+        ));
+    }
+
+    #[test]
+    fn compares_in_constant_time_by_content() {
+        assert!(constant_time_eq(b"s3cret", b"s3cret"));
+        assert!(constant_time_eq(b"", b""));
+        assert!(!constant_time_eq(b"s3cret", b"s3creT"));
+        assert!(!constant_time_eq(b"s3cret", b"s3cre"));
+    }
+}
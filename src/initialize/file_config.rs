@@ -1,5 +1,6 @@
+use std::collections::HashMap;
 use std::env;
-use std::fs::File;
+use std::fs::{self, File};
 use std::io::{BufReader, Read};
 use std::path::Path;
 
@@ -25,11 +26,83 @@ fn load_config() -> Value {
     // 文字列内に「{CUR}」が存在すれば、当プログラムが存在するディレクトリとみなして、カレントディレクトリに置換
     conf_toml_str = conf_toml_str.replace("{CUR}", &format!("{}", &cur_dir));
 
-    // 設定をtoml形式に変換して返す
-    return conf_toml_str.parse::<Value>().expect(&format!(
-        "couldn't parse config file to toml format.{}",
-        &conf_toml_str
-    ));
+    // 設定をtoml形式に変換する、秘密の値を含む可能性があるため設定の内容は出力しない
+    let mut config = match conf_toml_str.parse::<Value>() {
+        Err(e) => panic!("couldn't parse config file to toml format: {}", e),
+        Ok(config) => config,
+    };
+    // 文字列の値の「${VAR}」は環境変数、またはenvファイルの値に置換
+    // パース後に置換するため、コメント内の「${VAR}」や値に含まれる「"」の影響を受けない
+    let env_file = load_env_file(Path::new("config/.env"));
+    interpolate_values(&mut config, &env_file);
+    config
+}
+
+// 設定の全ての文字列の値を再帰的に置換する
+fn interpolate_values(value: &mut Value, env_file: &HashMap<String, String>) {
+    match value {
+        Value::String(text) => *text = interpolate_env(text, env_file),
+        Value::Array(values) => {
+            for value in values {
+                interpolate_values(value, env_file);
+            }
+        }
+        Value::Table(table) => {
+            for (_, value) in table.iter_mut() {
+                interpolate_values(value, env_file);
+            }
+        }
+        _ => {}
+    }
+}
+
+// KEY=VALUE 形式のenvファイルを読み込む、ファイルが存在しなければ空とする
+fn load_env_file(path: &Path) -> HashMap<String, String> {
+    let mut vars = HashMap::new();
+    let env_str = match fs::read_to_string(path) {
+        Err(_) => return vars,
+        Ok(env_str) => env_str,
+    };
+    for line in env_str.lines() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        match line.split_once('=') {
+            Some((key, value)) => {
+                let value = value.trim().trim_matches('"');
+                vars.insert(key.trim().to_string(), value.to_string());
+            }
+            None => panic!("couldn't parse {}: {}", path.display(), line),
+        }
+    }
+    vars
+}
+
+// 「${VAR}」を置換する、環境変数がenvファイルより優先される
+fn interpolate_env(text: &str, env_file: &HashMap<String, String>) -> String {
+    let mut interpolated = String::new();
+    let mut rest = text;
+    while let Some(start) = rest.find("${") {
+        interpolated.push_str(&rest[..start]);
+        let after = &rest[start + 2..];
+        let end = match after.find('}') {
+            None => panic!("unclosed ${{ in config file"),
+            Some(end) => end,
+        };
+        let name = &after[..end];
+        let value = match env::var(name) {
+            Ok(value) => value,
+            Err(_) => match env_file.get(name) {
+                Some(value) => value.clone(),
+                None => panic!("environment variable {} is not defined", name),
+            },
+        };
+        interpolated.push_str(&value);
+        rest = &after[end + 1..];
+    }
+    interpolated.push_str(rest);
+    interpolated
 }
 
 fn get_text_file(path: &Path, extension: &'static str) -> String {
@@ -60,3 +133,66 @@ fn get_text_file(path: &Path, extension: &'static str) -> String {
     }
     conf_toml_str
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn replaces_with_environment_variable_before_env_file() {
+        env::set_var("NELST_TEST_PRECEDENCE", "from_env");
+        let env_file =
+            HashMap::from([("NELST_TEST_PRECEDENCE".to_string(), "from_file".to_string())]);
+        assert_eq!(
+            interpolate_env("a-${NELST_TEST_PRECEDENCE}-b", &env_file),
+            "a-from_env-b"
+        );
+    }
+
+    #[test]
+    fn replaces_with_env_file_value() {
+        let env_file = HashMap::from([
+            ("NELST_TEST_FILE_ONLY".to_string(), "x".to_string()),
+            ("NELST_TEST_FILE_OTHER".to_string(), "y".to_string()),
+        ]);
+        assert_eq!(
+            interpolate_env("${NELST_TEST_FILE_ONLY}${NELST_TEST_FILE_OTHER}", &env_file),
+            "xy"
+        );
+        assert_eq!(
+            interpolate_env("no placeholder", &env_file),
+            "no placeholder"
+        );
+    }
+
+    #[test]
+    #[should_panic(expected = "NELST_TEST_UNDEFINED is not defined")]
+    fn panics_on_undefined_variable() {
+        interpolate_env("${NELST_TEST_UNDEFINED}", &HashMap::new());
+    }
+
+    #[test]
+    #[should_panic(expected = "unclosed")]
+    fn panics_on_unclosed_placeholder() {
+        interpolate_env("${NELST_TEST_UNCLOSED", &HashMap::new());
+    }
+
+    #[test]
+    fn interpolates_only_string_values() {
+        let mut config = r#"
+            # ${NELST_TEST_IN_COMMENT}
+            [load_test]
+            auth_token = "${NELST_TEST_TOKEN}"
+            targets = ["${NELST_TEST_TOKEN}", "plain"]
+            ttl = 0
+        "#
+        .parse::<Value>()
+        .unwrap();
+        let env_file = HashMap::from([("NELST_TEST_TOKEN".to_string(), "a\"b".to_string())]);
+        interpolate_values(&mut config, &env_file);
+        assert_eq!(config["load_test"]["auth_token"].as_str(), Some("a\"b"));
+        assert_eq!(config["load_test"]["targets"][0].as_str(), Some("a\"b"));
+        assert_eq!(config["load_test"]["targets"][1].as_str(), Some("plain"));
+        assert_eq!(config["load_test"]["ttl"].as_integer(), Some(0));
+    }
+}
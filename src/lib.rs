@@ -0,0 +1,11 @@
+pub mod affinity;
+pub mod auth;
+pub mod initialize;
+pub mod payload;
+pub mod safety;
+pub mod shutdown;
+pub mod stream;
+pub mod tcp_client;
+pub mod tcp_server;
+pub mod telemetry;
+pub mod udp_server;
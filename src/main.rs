@@ -1,14 +1,21 @@
-mod initialize;
-use initialize::file_config::CONFIG;
 use log::{debug, error, info};
 use log4rs;
+use nelst::initialize::file_config::CONFIG;
+use nelst::{affinity, payload, safety, shutdown, tcp_client, tcp_server, telemetry, udp_server};
+use serde_json::json;
 use std::net::SocketAddr;
-
-mod tcp_client;
-mod tcp_server;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
 
 fn main() {
-    log4rs::init_file("config/log4rs.yaml", Default::default()).unwrap();
+    // コンテナのログ収集向けに、JSONで出力する設定も選べる
+    let log_config = if CONFIG["load_test"]["json_log"].as_bool().unwrap() {
+        "config/log4rs_json.yaml"
+    } else {
+        "config/log4rs.yaml"
+    };
+    log4rs::init_file(log_config, Default::default()).unwrap();
     debug!("initilized logger");
 
     let is_server = CONFIG["load_test"]["is_server"].as_bool().unwrap();
@@ -24,7 +31,37 @@ fn main() {
         },
     );
 
-    execute_load_test(mode);
+    let repeat = CONFIG["load_test"]["repeat"].as_integer().unwrap();
+    let interval = CONFIG["load_test"]["interval"].as_integer().unwrap() as u64;
+    if CONFIG["load_test"]["dry_run"].as_bool().unwrap() {
+        print_plan(mode, repeat);
+        return;
+    }
+    // サーバーはSIGTERMとSIGINTで既存の接続を終えてから終了する
+    if is_server {
+        shutdown::listen();
+    }
+    // サーバーのプロセスの負荷を1秒毎に記録し、終了時に集計を出力する
+    let telemetry = if is_server && CONFIG["load_test"]["telemetry"].as_bool().unwrap() {
+        let telemetry = Arc::new(Mutex::new(telemetry::Telemetry::new()));
+        telemetry::Telemetry::spawn_sampler(Arc::clone(&telemetry));
+        Some(telemetry)
+    } else {
+        None
+    };
+    for round in 1..=repeat {
+        if shutdown::requested() {
+            break;
+        }
+        info!("Load Test Round: {}/{}", round, repeat);
+        execute_load_test(mode);
+        if round < repeat {
+            thread::sleep(Duration::from_secs(interval));
+        }
+    }
+    if let Some(telemetry) = telemetry {
+        telemetry.lock().unwrap().report();
+    }
 }
 
 pub fn execute_load_test(mode: (&str, &str, &str)) {
@@ -32,12 +69,48 @@ pub fn execute_load_test(mode: (&str, &str, &str)) {
     match (mode.0, mode.1) {
         ("client", "tcp") => {
             info!("Tcp Client");
+            let source = CONFIG["load_test"]["source"].as_str().unwrap();
+            let source_addr = source.parse::<SocketAddr>().unwrap();
             let target = CONFIG["load_test"]["target"].as_str().unwrap();
             let target_addr = target.parse::<SocketAddr>().unwrap();
+            let uds = CONFIG["load_test"]["uds"].as_str().unwrap();
+            // Unixドメインソケットの場合はローカルのため確認しない
+            if uds.is_empty() {
+                if let Err(reason) = check_target(target_addr) {
+                    error!("Refused target: {}", reason);
+                    return;
+                }
+            }
             let size_config_integer = CONFIG["load_test"]["packet_size"].as_integer().unwrap();
             let size_config = size_config_integer as usize;
-            let udp = tcp_client::TcpClient::new(target_addr, size_config);
-            udp.test_traffic_load(mode.2).unwrap();
+            let send_count = CONFIG["load_test"]["send_count"].as_integer().unwrap() as usize;
+            let duration = CONFIG["load_test"]["duration"].as_integer().unwrap() as u64;
+            let pattern = CONFIG["load_test"]["pattern"].as_str().unwrap();
+            let data = payload::generate(pattern, size_config).unwrap();
+            let verify = CONFIG["load_test"]["verify"].as_bool().unwrap();
+            let ttl = CONFIG["load_test"]["ttl"].as_integer().unwrap() as u32;
+            let tos = CONFIG["load_test"]["tos"].as_integer().unwrap() as u32;
+            let auth_token = CONFIG["load_test"]["auth_token"]
+                .as_str()
+                .unwrap()
+                .to_string();
+            let reconnect = CONFIG["load_test"]["reconnect"].as_integer().unwrap() as usize;
+            let udp = tcp_client::TcpClient::new(
+                source_addr,
+                target_addr,
+                data,
+                send_count,
+                duration,
+                verify,
+            )
+            .with_ip_header(ttl, tos)
+            .with_auth_token(auth_token)
+            .with_reconnect(reconnect)
+            .with_uds(uds);
+            // 再接続できずに終了した場合も、パニックせずに結果を出力する
+            if let Err(err) = udp.test_traffic_load(mode.2) {
+                error!("Tcp Client stopped: {}", err);
+            }
         }
         ("client", "udp") => {
             info!("Udp Client");
@@ -48,12 +121,146 @@ pub fn execute_load_test(mode: (&str, &str, &str)) {
             let bind_config = bind_config_str.parse().unwrap();
             let size_config_integer = CONFIG["load_test"]["packet_size"].as_integer().unwrap();
             let size_config = size_config_integer as usize;
-            let tcp = tcp_server::TcpServer::new(bind_config, size_config);
-            tcp.test_traffic_load().unwrap();
+            let read_delay = CONFIG["load_test"]["read_delay"].as_integer().unwrap() as u64;
+            let read_buffer = CONFIG["load_test"]["read_buffer"].as_integer().unwrap() as usize;
+            let reuseport = CONFIG["load_test"]["reuseport"].as_bool().unwrap();
+            let auth_token = CONFIG["load_test"]["auth_token"].as_str().unwrap();
+            let uds = CONFIG["load_test"]["uds"].as_str().unwrap();
+            let threads = CONFIG["load_test"]["threads"].as_integer().unwrap() as usize;
+            let pin_cores = CONFIG["load_test"]["pin_cores"].as_bool().unwrap();
+            let drain_timeout = CONFIG["load_test"]["drain_timeout"].as_integer().unwrap() as u64;
+            // クライアントの "to echo server" に対しては受信したデータをエコーする
+            let echo = mode.2 == "to echo server";
+            // reuseport の場合は threads 個 (0の場合はコア数分) のリスナーで並列に接続を受け付ける
+            // Unixドメインソケットは同じパスに複数バインドできないため、常に1つとする
+            let shards = if !reuseport || !uds.is_empty() {
+                1
+            } else if threads != 0 {
+                threads
+            } else {
+                thread::available_parallelism().map_or(1, |n| n.get())
+            };
+            info!("Tcp Server shards: {}, pin_cores: {}", shards, pin_cores);
+            // 受信レートは全てのリスナーの合計を1つのスレッドで出力する
+            let stats = Arc::new(tcp_server::IngestStats::default());
+            tcp_server::IngestStats::spawn_reporter(Arc::clone(&stats));
+            let handles: Vec<_> = (0..shards)
+                .map(|shard| {
+                    let stats = Arc::clone(&stats);
+                    thread::spawn(move || {
+                        // ベンチマークの再現性のため、リスナー毎に別のコアへ固定する
+                        if pin_cores {
+                            match affinity::pin_current_thread(shard) {
+                                Ok(core) => {
+                                    info!("Tcp Server shard {} pinned to core {}", shard, core)
+                                }
+                                Err(err) => {
+                                    error!("Tcp Server shard {} not pinned: {}", shard, err)
+                                }
+                            }
+                        }
+                        let tcp = tcp_server::TcpServer::new(
+                            bind_config,
+                            size_config,
+                            read_delay,
+                            read_buffer,
+                            reuseport,
+                            auth_token.to_string(),
+                        )
+                        .with_uds(uds)
+                        .with_echo(echo)
+                        .with_drain_timeout(drain_timeout)
+                        .with_stats(stats);
+                        tcp.test_traffic_load().unwrap();
+                    })
+                })
+                .collect();
+            for handle in handles {
+                handle.join().unwrap();
+            }
         }
         ("server", "udp") => {
             info!("Udp Server");
+            let bind_config_str = CONFIG["load_test"]["target"].as_str().unwrap();
+            let bind_config = bind_config_str.parse().unwrap();
+            let timestamps = CONFIG["load_test"]["timestamps"].as_bool().unwrap();
+            let auth_token = CONFIG["load_test"]["auth_token"]
+                .as_str()
+                .unwrap()
+                .to_string();
+            let udp = udp_server::UdpServer::new(bind_config, timestamps, auth_token);
+            udp.test_traffic_load().unwrap();
         }
         _ => error!("Errors in the configuration file"),
     }
 }
+
+fn check_target(target_addr: SocketAddr) -> Result<(), String> {
+    let allowed_networks: Vec<&str> = CONFIG["safety"]["allowed_networks"]
+        .as_array()
+        .unwrap()
+        .iter()
+        .map(|network| network.as_str().unwrap())
+        .collect();
+    let i_own_this_target = CONFIG["safety"]["i_own_this_target"].as_bool().unwrap();
+    safety::check_target(target_addr.ip(), &allowed_networks, i_own_this_target)
+}
+
+/// トラフィックを送信せずに、設定の検証結果と実行計画をJSONで出力する
+fn print_plan(mode: (&str, &str, &str), repeat: i64) {
+    let mut errors = Vec::new();
+    // 実行時と同じく、Unixドメインソケットの場合は接続先を確認しない
+    let uds = CONFIG["load_test"]["uds"].as_str().unwrap();
+    for key in ["source", "target"] {
+        let addr_str = CONFIG["load_test"][key].as_str().unwrap();
+        match addr_str.parse::<SocketAddr>() {
+            Ok(addr) => {
+                if mode.0 == "client" && key == "target" && uds.is_empty() {
+                    if let Err(reason) = check_target(addr) {
+                        errors.push(reason);
+                    }
+                }
+            }
+            Err(e) => errors.push(format!("invalid {} {}: {}", key, addr_str, e)),
+        }
+    }
+
+    let packet_size = CONFIG["load_test"]["packet_size"].as_integer().unwrap();
+    if mode.0 == "client" {
+        let pattern = CONFIG["load_test"]["pattern"].as_str().unwrap();
+        if let Err(e) = payload::generate(pattern, packet_size as usize) {
+            errors.push(format!("invalid pattern {}: {}", pattern, e));
+        }
+    }
+    let send_count = CONFIG["load_test"]["send_count"].as_integer().unwrap();
+    // サーバーはクライアント次第、送信回数が無制限の場合は経過時間次第のため見積もらない
+    let packets = match (mode.0, mode.1, mode.2) {
+        ("client", "tcp", "send only") => Some(repeat),
+        ("client", "tcp", _) if send_count != 0 => Some(send_count * repeat),
+        _ => None,
+    };
+    let plan = json!({
+        "mode": { "role": mode.0, "protocol": mode.1, "handle": mode.2 },
+        "config": redacted_config(),
+        "valid": errors.is_empty(),
+        "errors": errors,
+        "estimate": {
+            "packets": packets,
+            "bytes": packets.map(|packets| packets * packet_size),
+        },
+    });
+    println!("{}", serde_json::to_string_pretty(&plan).unwrap());
+}
+
+/// 実行計画に出力する設定、認証トークンなどの秘密の値は伏せる
+fn redacted_config() -> serde_json::Value {
+    let mut config = serde_json::to_value(&*CONFIG).unwrap();
+    for key in ["auth_token"] {
+        if let Some(value) = config["load_test"].get_mut(key) {
+            if value.as_str().is_some_and(|secret| !secret.is_empty()) {
+                *value = json!("<redacted>");
+            }
+        }
+    }
+    config
+}
@@ -0,0 +1,97 @@
+use rand::RngCore;
+use std::fs;
+use std::io;
+
+/// 送信データを指定したパターンで作成する
+/// - "fixed": 0x31 の繰り返し
+/// - "incrementing": 0x00 から 0xff を繰り返す
+/// - "random": ランダムなバイト列
+/// - "file:<path>": ファイルの内容を繰り返す
+pub fn generate(pattern: &str, size: usize) -> io::Result<Vec<u8>> {
+    let data = match pattern {
+        "fixed" => vec![0x31; size],
+        "incrementing" => (0..size).map(|i| i as u8).collect(),
+        "random" => {
+            let mut data = vec![0; size];
+            rand::thread_rng().fill_bytes(&mut data);
+            data
+        }
+        _ => match pattern.strip_prefix("file:") {
+            Some(path) => {
+                let contents = fs::read(path)?;
+                if contents.is_empty() {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        format!("{} is empty", path),
+                    ));
+                }
+                contents.iter().cycle().take(size).cloned().collect()
+            }
+            None => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!("unknown pattern: {}", pattern),
+                ))
+            }
+        },
+    };
+    Ok(data)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+
+    #[test]
+    fn generates_fixed_pattern() {
+        assert_eq!(generate("fixed", 4).unwrap(), vec![0x31; 4]);
+        assert!(generate("fixed", 0).unwrap().is_empty());
+    }
+
+    #[test]
+    fn generates_incrementing_pattern_wrapping_at_256() {
+        let data = generate("incrementing", 258).unwrap();
+        assert_eq!(data.len(), 258);
+        assert_eq!(&data[..3], &[0x00, 0x01, 0x02]);
+        assert_eq!(data[255], 0xff);
+        assert_eq!(&data[256..], &[0x00, 0x01]);
+    }
+
+    #[test]
+    fn generates_random_pattern_of_size() {
+        assert_eq!(generate("random", 1024).unwrap().len(), 1024);
+    }
+
+    #[test]
+    fn repeats_file_contents() {
+        let path = env::temp_dir().join(format!("nelst_payload_{}.bin", std::process::id()));
+        fs::write(&path, b"abc").unwrap();
+        let data = generate(&format!("file:{}", path.display()), 7).unwrap();
+        fs::remove_file(&path).unwrap();
+        assert_eq!(data, b"abcabca");
+    }
+
+    #[test]
+    fn rejects_empty_file() {
+        let path = env::temp_dir().join(format!("nelst_payload_empty_{}.bin", std::process::id()));
+        fs::write(&path, b"").unwrap();
+        let err = generate(&format!("file:{}", path.display()), 7).unwrap_err();
+        fs::remove_file(&path).unwrap();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn rejects_missing_file_and_unknown_pattern() {
+        assert_eq!(
+            generate("file:/nonexistent/nelst_payload", 1)
+                .unwrap_err()
+                .kind(),
+            io::ErrorKind::NotFound
+        );
+        assert_eq!(
+            generate("zeros", 1).unwrap_err().kind(),
+            io::ErrorKind::InvalidInput
+        );
+    }
+}
@@ -0,0 +1,131 @@
+use std::net::IpAddr;
+
+/// 負荷テストの宛先として許可されているか確認する
+/// 許可されていない場合は理由を返す
+pub fn check_target(
+    target: IpAddr,
+    allowed_networks: &[&str],
+    i_own_this_target: bool,
+) -> Result<(), String> {
+    // ブロードキャスト、マルチキャストは常に拒否
+    let is_broadcast = match target {
+        IpAddr::V4(addr) => addr.is_broadcast(),
+        IpAddr::V6(_) => false,
+    };
+    if is_broadcast || target.is_multicast() {
+        return Err(format!("{} is a broadcast or multicast address", target));
+    }
+
+    for network in allowed_networks {
+        match contains(network, target) {
+            Ok(true) => return Ok(()),
+            Ok(false) => {}
+            Err(e) => return Err(e),
+        }
+    }
+
+    // 許可リスト外の宛先は、所有していることを明示した場合のみ許可
+    if i_own_this_target {
+        return Ok(());
+    }
+    Err(format!(
+        "{} is not in safety.allowed_networks, set safety.i_own_this_target = true to test it",
+        target
+    ))
+}
+
+/// CIDR表記 (例: "192.168.0.0/16") のネットワークに含まれるか判定する
+fn contains(network: &str, target: IpAddr) -> Result<bool, String> {
+    let (addr_str, prefix_str) = match network.split_once('/') {
+        Some((addr_str, prefix_str)) => (addr_str, prefix_str),
+        None => return Err(format!("invalid network: {}", network)),
+    };
+    let addr = addr_str
+        .parse::<IpAddr>()
+        .map_err(|e| format!("invalid network {}: {}", network, e))?;
+    let prefix = prefix_str
+        .parse::<u32>()
+        .map_err(|e| format!("invalid network {}: {}", network, e))?;
+
+    let max_prefix = if addr.is_ipv4() { 32 } else { 128 };
+    if prefix > max_prefix {
+        return Err(format!("invalid prefix length: {}", network));
+    }
+
+    match (addr, target) {
+        (IpAddr::V4(addr), IpAddr::V4(target)) => {
+            let mask = u32::MAX.checked_shl(32 - prefix).unwrap_or(0);
+            Ok(u32::from(addr) & mask == u32::from(target) & mask)
+        }
+        (IpAddr::V6(addr), IpAddr::V6(target)) => {
+            let mask = u128::MAX.checked_shl(128 - prefix).unwrap_or(0);
+            Ok(u128::from(addr) & mask == u128::from(target) & mask)
+        }
+        // アドレスファミリーが異なる場合は含まれない
+        _ => Ok(false),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ip(addr: &str) -> IpAddr {
+        addr.parse().unwrap()
+    }
+
+    #[test]
+    fn contains_any_address_with_zero_prefix() {
+        assert_eq!(contains("0.0.0.0/0", ip("203.0.113.1")), Ok(true));
+        assert_eq!(contains("::/0", ip("2001:db8::1")), Ok(true));
+    }
+
+    #[test]
+    fn contains_only_the_address_with_full_prefix() {
+        assert_eq!(contains("192.168.1.1/32", ip("192.168.1.1")), Ok(true));
+        assert_eq!(contains("192.168.1.1/32", ip("192.168.1.2")), Ok(false));
+        assert_eq!(contains("::1/128", ip("::1")), Ok(true));
+        assert_eq!(contains("::1/128", ip("::2")), Ok(false));
+    }
+
+    #[test]
+    fn contains_addresses_within_prefix() {
+        assert_eq!(contains("172.16.0.0/12", ip("172.31.255.255")), Ok(true));
+        assert_eq!(contains("172.16.0.0/12", ip("172.32.0.0")), Ok(false));
+        assert_eq!(contains("fc00::/7", ip("fd12::1")), Ok(true));
+        assert_eq!(contains("fc00::/7", ip("fe80::1")), Ok(false));
+    }
+
+    #[test]
+    fn does_not_contain_other_address_family() {
+        assert_eq!(contains("0.0.0.0/0", ip("::1")), Ok(false));
+        assert_eq!(contains("::/0", ip("127.0.0.1")), Ok(false));
+    }
+
+    #[test]
+    fn rejects_invalid_network() {
+        assert!(contains("10.0.0.0/33", ip("10.0.0.1")).is_err());
+        assert!(contains("::/129", ip("::1")).is_err());
+        assert!(contains("10.0.0.0/x", ip("10.0.0.1")).is_err());
+        assert!(contains("10.0.0.0", ip("10.0.0.1")).is_err());
+        assert!(contains("10.0.0/8", ip("10.0.0.1")).is_err());
+        assert!(check_target(ip("10.0.0.1"), &["10.0.0.0/33"], true).is_err());
+    }
+
+    #[test]
+    fn refuses_broadcast_and_multicast_even_if_owned() {
+        let allowed = ["0.0.0.0/0", "::/0"];
+        assert!(check_target(ip("255.255.255.255"), &allowed, true).is_err());
+        assert!(check_target(ip("224.0.0.1"), &allowed, true).is_err());
+        assert!(check_target(ip("ff02::1"), &allowed, true).is_err());
+    }
+
+    #[test]
+    fn allows_target_in_allowed_networks_or_owned() {
+        let allowed = ["127.0.0.0/8", "::1/128"];
+        assert!(check_target(ip("127.0.0.1"), &allowed, false).is_ok());
+        assert!(check_target(ip("::1"), &allowed, false).is_ok());
+        assert!(check_target(ip("203.0.113.1"), &allowed, false).is_err());
+        assert!(check_target(ip("203.0.113.1"), &allowed, true).is_ok());
+    }
+}
@@ -0,0 +1,86 @@
+use lazy_static::lazy_static;
+use log::{error, info};
+use mio::Waker;
+use std::io;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
+
+lazy_static! {
+    // 停止を要求された場合に起こす、各サーバーのpollのwaker
+    static ref WAKERS: Mutex<Vec<Arc<Waker>>> = Mutex::new(Vec::new());
+}
+
+static REQUESTED: AtomicBool = AtomicBool::new(false);
+
+/// SIGTERMとSIGINTを待ち受けるスレッドを起動し、受信した場合はサーバーに停止を要求する
+/// コンテナのオーケストレーターからの停止で、接続を終えてから終了できるようにする
+pub fn listen() {
+    thread::spawn(|| {
+        let runtime = match tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()
+        {
+            Ok(runtime) => runtime,
+            Err(err) => {
+                error!("Unable to listen for shutdown signals: {}", err);
+                return;
+            }
+        };
+        match runtime.block_on(wait_for_signal()) {
+            Ok(signal) => {
+                info!("Received {}, shutting down", signal);
+                request();
+            }
+            Err(err) => error!("Unable to listen for shutdown signals: {}", err),
+        }
+    });
+}
+
+#[cfg(unix)]
+async fn wait_for_signal() -> io::Result<&'static str> {
+    use tokio::signal::unix::{signal, SignalKind};
+    let mut terminate = signal(SignalKind::terminate())?;
+    tokio::select! {
+        _ = terminate.recv() => Ok("SIGTERM"),
+        result = tokio::signal::ctrl_c() => result.map(|_| "SIGINT"),
+    }
+}
+
+#[cfg(not(unix))]
+async fn wait_for_signal() -> io::Result<&'static str> {
+    tokio::signal::ctrl_c().await.map(|_| "Ctrl-C")
+}
+
+/// 停止を要求し、登録されている全てのサーバーを起こす
+pub fn request() {
+    REQUESTED.store(true, Ordering::SeqCst);
+    for waker in WAKERS.lock().unwrap().iter() {
+        if let Err(err) = waker.wake() {
+            error!("Unable to wake for shutdown: {}", err);
+        }
+    }
+}
+
+/// 停止を要求されているかどうか
+pub fn requested() -> bool {
+    REQUESTED.load(Ordering::SeqCst)
+}
+
+/// 停止を要求された場合に起こすwakerを登録する、既に要求されている場合はすぐに起こす
+pub fn register(waker: Arc<Waker>) -> io::Result<()> {
+    let mut wakers = WAKERS.lock().unwrap();
+    if requested() {
+        waker.wake()?;
+    }
+    wakers.push(waker);
+    Ok(())
+}
+
+/// 終了したサーバーのwakerの登録を解除する
+pub fn unregister(waker: &Arc<Waker>) {
+    WAKERS
+        .lock()
+        .unwrap()
+        .retain(|registered| !Arc::ptr_eq(registered, waker));
+}
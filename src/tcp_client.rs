@@ -1,33 +1,121 @@
+use crate::auth;
+use crate::stream::Stream;
 use log::{debug, info};
 use mio::event::Event;
 use mio::net::TcpStream;
-use mio::{Events, Interest, Poll, Token, Waker};
+#[cfg(unix)]
+use mio::net::UnixStream;
+use mio::{Events, Interest, Poll, Registry, Token, Waker};
+use socket2::{Domain, Protocol, Socket, Type};
+use std::cell::Cell;
 use std::io::{self, Read, Write};
+use std::path::{Path, PathBuf};
 use std::str::from_utf8;
-use std::sync::{Arc, RwLock};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
 use std::thread;
-use std::time::Duration;
+use std::time::{Duration, Instant};
 
 pub struct TcpClient {
+    source_addr: std::net::SocketAddr,
     target_addr: std::net::SocketAddr,
+    uds: Option<PathBuf>,
     data: Vec<u8>,
+    send_count: usize,
+    duration: Duration,
+    verify: bool,
+    ttl: u32,
+    tos: u32,
+    auth_token: String,
+    reconnect: usize,
+    reconnects: Cell<usize>,
+    interrupted: Cell<bool>,
+    sent_bytes: Cell<usize>,
+    // 送信できた回数、wakerスレッドが終了条件の確認に使うためアトミックに数える
+    sent_count: Arc<AtomicUsize>,
+    received_bytes: Cell<usize>,
+    verify_offset: Cell<usize>,
+    corrupted_bytes: Cell<usize>,
 }
 
 impl TcpClient {
     const CLIENT: Token = Token(2);
     const WAKER: Token = Token(1);
+    // 到達できない宛先への接続を待つ時間
+    const CONNECT_TIMEOUT: Duration = Duration::from_secs(5);
+    // 終了条件に達した後、送信済みデータのエコーを待つ時間
+    const DRAIN_TIMEOUT: Duration = Duration::from_secs(1);
 
-    pub fn new(target_addr_config: std::net::SocketAddr, packet_size_config: usize) -> TcpClient {
+    pub fn new(
+        source_addr_config: std::net::SocketAddr,
+        target_addr_config: std::net::SocketAddr,
+        data_config: Vec<u8>,
+        send_count_config: usize,
+        duration_config: u64,
+        verify_config: bool,
+    ) -> TcpClient {
         info!(
-            "config target_addr: {}, packet_size: {}",
-            target_addr_config, packet_size_config
+            "config source_addr: {}, target_addr: {}, packet_size: {}, send_count: {}, duration: {}s, verify: {}",
+            source_addr_config,
+            target_addr_config,
+            data_config.len(),
+            send_count_config,
+            duration_config,
+            verify_config
         );
         TcpClient {
+            source_addr: source_addr_config,
             target_addr: target_addr_config,
-            data: vec![0x31; packet_size_config],
+            uds: None,
+            data: data_config,
+            send_count: send_count_config,
+            duration: Duration::from_secs(duration_config),
+            verify: verify_config,
+            ttl: 0,
+            tos: 0,
+            auth_token: String::new(),
+            reconnect: 0,
+            reconnects: Cell::new(0),
+            interrupted: Cell::new(false),
+            sent_bytes: Cell::new(0),
+            sent_count: Arc::new(AtomicUsize::new(0)),
+            received_bytes: Cell::new(0),
+            verify_offset: Cell::new(0),
+            corrupted_bytes: Cell::new(0),
         }
     }
 
+    /// 送信パケットのIPヘッダーに設定するTTLとToS、0の場合はOSのデフォルトとする
+    pub fn with_ip_header(mut self, ttl_config: u32, tos_config: u32) -> TcpClient {
+        info!("config ttl: {}, tos: {}", ttl_config, tos_config);
+        self.ttl = ttl_config;
+        self.tos = tos_config;
+        self
+    }
+
+    /// 接続直後に送信する認証トークン、空の場合は認証しない
+    pub fn with_auth_token(mut self, auth_token_config: String) -> TcpClient {
+        info!("config auth: {}", !auth_token_config.is_empty());
+        self.auth_token = auth_token_config;
+        self
+    }
+
+    /// 接続が途中で切断された場合に再接続する最大回数、0の場合は再接続しない
+    pub fn with_reconnect(mut self, reconnect_config: usize) -> TcpClient {
+        info!("config reconnect: {}", reconnect_config);
+        self.reconnect = reconnect_config;
+        self
+    }
+
+    /// 接続先のUnixドメインソケットのパス、空の場合はTCPで接続する
+    pub fn with_uds(mut self, uds_config: &str) -> TcpClient {
+        info!("config uds: {}", uds_config);
+        if !uds_config.is_empty() {
+            self.uds = Some(PathBuf::from(uds_config));
+        }
+        self
+    }
+
     pub fn test_traffic_load(&self, handle: &str) -> io::Result<()> {
         let tmp = &self.data;
         if let Ok(str_buf) = from_utf8(tmp) {
@@ -38,29 +126,40 @@ impl TcpClient {
         let mut poll = Poll::new()?;
         let mut events = Events::with_capacity(128);
 
-        let mut client = TcpStream::connect(self.target_addr)?;
+        let mut client = self.connect()?;
         poll.registry().register(
             &mut client,
             Self::CLIENT,
             Interest::READABLE | Interest::WRITABLE,
         )?;
 
+        // 受信バッファは接続中に使い回す
+        let mut received_data = vec![0; 4096];
+
         let waker = Arc::new(Waker::new(poll.registry(), Self::WAKER)?);
         let waker_clone = waker.clone();
-        let counter = Arc::new(RwLock::new(0));
+        // wakerの回数ではなく実際に送信できた回数で終了するため、再接続の待機中は増えない
+        let counter = Arc::clone(&self.sent_count);
+        let start = Instant::now();
         {
             let counter = Arc::clone(&counter);
+            let send_count = self.send_count;
+            let duration = self.duration;
 
             thread::spawn(move || {
-                while *counter.read().unwrap() < 100 {
+                while Self::stop_reason(
+                    send_count,
+                    duration,
+                    counter.load(Ordering::Relaxed),
+                    start.elapsed(),
+                )
+                .is_none()
+                {
                     thread::sleep(Duration::from_nanos(1000));
-                    info!("wake {}", *counter.read().unwrap());
-                    {
-                        let mut w = counter.write().unwrap();
-                        *w += 1;
-                    }
                     waker_clone.wake().expect("unable to wake");
                 }
+                // 経過時間で終了した場合に、メインスレッドが終了条件を確認できるように最後に起こす
+                waker_clone.wake().expect("unable to wake");
             });
         }
 
@@ -80,17 +179,50 @@ impl TcpClient {
                                 // 接続維持
                                 Ok(false) => {}
                                 // 接続終了
-                                Ok(true) => return Ok(()),
-                                Err(err) => return Err(err),
+                                Ok(true) => {
+                                    self.log_interruption();
+                                    return Ok(());
+                                }
+                                Err(err) => {
+                                    info!("Connection error: {}", err);
+                                    if !self.reconnect(poll.registry(), &mut client)? {
+                                        self.log_interruption();
+                                        return Err(err);
+                                    }
+                                }
                             }
                         }
                         "to echo server" => {
-                            match self.handle_echo_server_connection_event(&mut client, event) {
+                            let error = match self.handle_echo_server_connection_event(
+                                &mut client,
+                                event,
+                                &mut received_data,
+                            ) {
                                 // 接続維持
-                                Ok(false) => {}
-                                // 接続終了
-                                Ok(true) => return Ok(()),
-                                Err(err) => return Err(err),
+                                Ok(false) => continue,
+                                // サーバーが接続をクローズした
+                                Ok(true) => None,
+                                Err(err) => {
+                                    info!("Connection error: {}", err);
+                                    Some(err)
+                                }
+                            };
+                            // 終了条件に達した後の切断は正常終了、達する前の切断は途中の切断として再接続
+                            let finished = Self::stop_reason(
+                                self.send_count,
+                                self.duration,
+                                counter.load(Ordering::Relaxed),
+                                start.elapsed(),
+                            )
+                            .is_some();
+                            if finished || !self.reconnect(poll.registry(), &mut client)? {
+                                self.log_throughput(start.elapsed());
+                                self.log_verification();
+                                self.log_interruption();
+                                return match error {
+                                    Some(err) if !finished => Err(err),
+                                    _ => Ok(()),
+                                };
                             }
                         }
                         _ => unreachable!(),
@@ -98,18 +230,198 @@ impl TcpClient {
                     _ => unreachable!(),
                 }
             }
-            debug!("count {}", *counter.read().unwrap());
-            if *counter.read().unwrap() >= 100 {
-                info!("end");
+            let count = counter.load(Ordering::Relaxed);
+            debug!("count {}", count);
+            if let Some(reason) =
+                Self::stop_reason(self.send_count, self.duration, count, start.elapsed())
+            {
+                info!(
+                    "end: {} (count {}, elapsed {:?})",
+                    reason,
+                    count,
+                    start.elapsed()
+                );
+                if handle == "to echo server" {
+                    let elapsed = start.elapsed();
+                    self.drain_echo(&mut poll, &mut events, &mut client, &mut received_data)?;
+                    self.log_throughput(elapsed);
+                    self.log_verification();
+                }
+                self.log_interruption();
                 break;
             }
         }
         return Ok(());
     }
 
+    /// 接続前にソケットオプションを設定するため、socket2で接続してからmioへ渡す
+    fn connect(&self) -> io::Result<Stream> {
+        if let Some(path) = &self.uds {
+            return self.connect_uds(path);
+        }
+        // ソケットのアドレスファミリーは接続先に合わせる
+        let socket = Socket::new(
+            Domain::for_address(self.target_addr),
+            Type::STREAM,
+            Some(Protocol::TCP),
+        )?;
+        socket.set_reuse_address(true)?;
+        self.set_ip_header(&socket)?;
+        // 送信元アドレスをバインドしてから接続、ポート0の場合はOSがエフェメラルポートを割り当てる
+        if self.source_addr.is_ipv4() == self.target_addr.is_ipv4() {
+            socket.bind(&self.source_addr.into())?;
+        } else if self.source_addr.port() == 0
+            && (self.source_addr.ip().is_loopback() || self.source_addr.ip().is_unspecified())
+        {
+            // 既定の送信元はアドレスファミリーが異なる接続先ではバインドせず、OSに任せる
+            info!(
+                "Skip binding source {} for target {}",
+                self.source_addr, self.target_addr
+            );
+        } else {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!(
+                    "source {} and target {} are different address families",
+                    self.source_addr, self.target_addr
+                ),
+            ));
+        }
+        // 到達できない宛先でカーネルのSYNのタイムアウトまで待たないように、タイムアウトを指定する
+        socket.connect_timeout(&self.target_addr.into(), Self::CONNECT_TIMEOUT)?;
+        // 認証メッセージはブロッキングのまま送り切る
+        if !self.auth_token.is_empty() {
+            (&socket).write_all(&auth::message(&self.auth_token))?;
+        }
+        socket.set_nonblocking(true)?;
+        Ok(Stream::Tcp(TcpStream::from_std(socket.into())))
+    }
+
+    /// TTLとToSを設定する、IPv6の場合はホップリミットとトラフィッククラスとする
+    fn set_ip_header(&self, socket: &Socket) -> io::Result<()> {
+        if self.target_addr.is_ipv4() {
+            if self.ttl != 0 {
+                socket.set_ttl(self.ttl)?;
+            }
+            if self.tos != 0 {
+                socket.set_tos(self.tos)?;
+            }
+        } else {
+            if self.ttl != 0 {
+                socket.set_unicast_hops_v6(self.ttl)?;
+            }
+            if self.tos != 0 {
+                Self::set_tclass_v6(socket, self.tos)?;
+            }
+        }
+        Ok(())
+    }
+
+    #[cfg(any(
+        target_os = "android",
+        target_os = "freebsd",
+        target_os = "linux",
+        target_os = "macos"
+    ))]
+    fn set_tclass_v6(socket: &Socket, tclass: u32) -> io::Result<()> {
+        socket.set_tclass_v6(tclass)
+    }
+
+    #[cfg(not(any(
+        target_os = "android",
+        target_os = "freebsd",
+        target_os = "linux",
+        target_os = "macos"
+    )))]
+    fn set_tclass_v6(_socket: &Socket, _tclass: u32) -> io::Result<()> {
+        Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            "IPv6 traffic class is not supported on this platform",
+        ))
+    }
+
+    /// Unixドメインソケットへ接続する、TTLなどのIPのオプションは使わない
+    #[cfg(unix)]
+    fn connect_uds(&self, path: &Path) -> io::Result<Stream> {
+        let mut stream = std::os::unix::net::UnixStream::connect(path)?;
+        // 認証メッセージはブロッキングのまま送り切る
+        if !self.auth_token.is_empty() {
+            stream.write_all(&auth::message(&self.auth_token))?;
+        }
+        stream.set_nonblocking(true)?;
+        Ok(Stream::Unix(UnixStream::from_std(stream)))
+    }
+
+    #[cfg(not(unix))]
+    fn connect_uds(&self, _path: &Path) -> io::Result<Stream> {
+        Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            "Unix domain sockets are not supported on this platform",
+        ))
+    }
+
+    /// 途中で切断された接続を、指数バックオフで待機してから再接続する
+    /// 再接続の回数が上限に達した場合、`false`を返す
+    fn reconnect(&self, registry: &Registry, client: &mut Stream) -> io::Result<bool> {
+        self.interrupted.set(true);
+        registry.deregister(client)?;
+        while self.reconnects.get() < self.reconnect {
+            let attempt = self.reconnects.get() + 1;
+            self.reconnects.set(attempt);
+            // 100ms から倍々に待機、最大6.4秒
+            let backoff = Duration::from_millis(100 << (attempt - 1).min(6));
+            info!(
+                "Reconnect {}/{} after {:?}",
+                attempt, self.reconnect, backoff
+            );
+            thread::sleep(backoff);
+            match self.connect() {
+                Ok(mut stream) => {
+                    registry.register(
+                        &mut stream,
+                        Self::CLIENT,
+                        Interest::READABLE | Interest::WRITABLE,
+                    )?;
+                    *client = stream;
+                    // 新しい接続のエコーは送信データの先頭から始まる
+                    self.verify_offset.set(0);
+                    return Ok(true);
+                }
+                Err(err) => info!("Reconnect failed: {}", err),
+            }
+        }
+        Ok(false)
+    }
+
+    /// 途中で切断されたかどうかと、再接続した回数を出力する
+    fn log_interruption(&self) {
+        info!(
+            "Interrupted: {}, reconnects: {}",
+            self.interrupted.get(),
+            self.reconnects.get()
+        );
+    }
+
+    /// 送信回数または経過時間のいずれかが上限に達した場合、終了理由を返す
+    /// 上限が0の場合は無制限とする
+    fn stop_reason(
+        send_count: usize,
+        duration: Duration,
+        count: usize,
+        elapsed: Duration,
+    ) -> Option<&'static str> {
+        if send_count != 0 && count >= send_count {
+            return Some("reached send_count");
+        }
+        if !duration.is_zero() && elapsed >= duration {
+            return Some("reached duration");
+        }
+        None
+    }
+
     fn handle_send_only_connection_event(
         &self,
-        connection: &mut TcpStream,
+        connection: &mut Stream,
         event: &Event,
     ) -> io::Result<bool> {
         match connection.write(&self.data) {
@@ -117,6 +429,7 @@ impl TcpClient {
                 return Err(io::ErrorKind::WriteZero.into());
             }
             Ok(_) => {
+                self.sent_count.fetch_add(1, Ordering::Relaxed);
                 let tmp = &self.data;
                 if let Ok(str_buf) = from_utf8(tmp) {
                     info!("Sent data: {}", str_buf.trim_end());
@@ -137,8 +450,9 @@ impl TcpClient {
 
     fn handle_echo_server_connection_event(
         &self,
-        connection: &mut TcpStream,
+        connection: &mut Stream,
         event: &Event,
+        received_data: &mut Vec<u8>,
     ) -> io::Result<bool> {
         // Wake のタイミングのみ書き込む、clientの受信イベントはis_writable()=trueのため
         if !event.is_writable() {
@@ -152,10 +466,17 @@ impl TcpClient {
                     }
                     return Err(io::ErrorKind::WriteZero.into());
                 }
-                Ok(_) => {}
+                Ok(n) => {
+                    self.sent_bytes.set(self.sent_bytes.get() + n);
+                    self.sent_count.fetch_add(1, Ordering::Relaxed);
+                }
                 Err(ref err) if self.would_block(err) => {}
                 Err(ref err) if self.interrupted(err) => {
-                    return self.handle_echo_server_connection_event(connection, event);
+                    return self.handle_echo_server_connection_event(
+                        connection,
+                        event,
+                        received_data,
+                    );
                 }
                 // 他のエラーは致命的なエラーとして処理
                 Err(err) => return Err(err),
@@ -164,7 +485,6 @@ impl TcpClient {
 
         if event.is_readable() {
             let mut connection_closed = false;
-            let mut received_data = vec![0; 4096];
             let mut bytes_read = 0;
             // 該当の接続から受信できる可能性がある
             loop {
@@ -195,6 +515,11 @@ impl TcpClient {
                 } else {
                     info!("Received (none UTF-8) data: {:?}", received_data);
                 }
+                self.received_bytes
+                    .set(self.received_bytes.get() + received_data.len());
+                if self.verify {
+                    self.verify_echo(received_data);
+                }
             }
 
             if connection_closed {
@@ -205,6 +530,91 @@ impl TcpClient {
         return Ok(false);
     }
 
+    /// 送信済みのデータがエコーされるまで、DRAIN_TIMEOUTを上限に受信だけを続ける
+    /// 最後に送ったデータが送信中のまま欠損として数えられないようにする
+    fn drain_echo(
+        &self,
+        poll: &mut Poll,
+        events: &mut Events,
+        connection: &mut Stream,
+        received_data: &mut [u8],
+    ) -> io::Result<()> {
+        let deadline = Instant::now() + Self::DRAIN_TIMEOUT;
+        loop {
+            // エッジトリガーのため、イベントを待つ前に受信済みのデータを読み切る
+            loop {
+                match connection.read(received_data) {
+                    Ok(0) => return Ok(()),
+                    Ok(n) => {
+                        self.received_bytes.set(self.received_bytes.get() + n);
+                        if self.verify {
+                            self.verify_echo(&received_data[..n]);
+                        }
+                    }
+                    Err(ref err) if self.would_block(err) => break,
+                    Err(ref err) if self.interrupted(err) => continue,
+                    Err(err) => return Err(err),
+                }
+            }
+            let now = Instant::now();
+            if self.received_bytes.get() >= self.sent_bytes.get() || now >= deadline {
+                return Ok(());
+            }
+            poll.poll(events, Some(deadline - now))?;
+        }
+    }
+
+    /// エコーされたデータを送信データと比較し、一致しないバイト数を数える
+    /// 送信データを繰り返し送っているため、受信済みのバイト数から期待するバイトを求める
+    fn verify_echo(&self, received_data: &[u8]) {
+        if self.data.is_empty() {
+            return;
+        }
+        let offset = self.verify_offset.get();
+        let corrupted = received_data
+            .iter()
+            .enumerate()
+            .filter(|(i, byte)| **byte != self.data[(offset + i) % self.data.len()])
+            .count();
+        self.verify_offset.set(offset + received_data.len());
+        self.corrupted_bytes
+            .set(self.corrupted_bytes.get() + corrupted);
+    }
+
+    /// 接続を維持したまま送受信を続けた間の、持続的なスループットを出力する
+    fn log_throughput(&self, elapsed: Duration) {
+        let secs = elapsed.as_secs_f64();
+        if secs == 0.0 {
+            return;
+        }
+        let sent = self.sent_bytes.get();
+        let received = self.received_bytes.get();
+        info!(
+            "Throughput sent: {} bytes ({:.3} MB/s), received: {} bytes ({:.3} MB/s), elapsed: {:?}",
+            sent,
+            sent as f64 / secs / 1_000_000.0,
+            received,
+            received as f64 / secs / 1_000_000.0,
+            elapsed
+        );
+    }
+
+    /// 破損と欠損を分けて出力する
+    fn log_verification(&self) {
+        if !self.verify {
+            return;
+        }
+        let sent = self.sent_bytes.get();
+        let received = self.received_bytes.get();
+        info!(
+            "Verify sent: {} bytes, received: {} bytes, lost: {} bytes, corrupted: {} bytes",
+            sent,
+            received,
+            sent.saturating_sub(received),
+            self.corrupted_bytes.get()
+        );
+    }
+
     fn would_block(&self, err: &io::Error) -> bool {
         err.kind() == io::ErrorKind::WouldBlock
     }
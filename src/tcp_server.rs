@@ -1,32 +1,147 @@
+use crate::auth;
+use crate::shutdown;
+use crate::stream::{Listener, Stream};
 use log::info;
 use mio::event::Event;
-use mio::net::{TcpListener, TcpStream};
+use mio::net::TcpSocket;
+#[cfg(unix)]
+use mio::net::UnixListener;
 use mio::{Events, Interest, Poll, Registry, Token, Waker};
+use serde_json::json;
 use std::collections::HashMap;
+#[cfg(unix)]
+use std::fs;
 use std::io::{self, Read, Write};
+#[cfg(unix)]
+use std::os::unix::fs::FileTypeExt;
+use std::path::{Path, PathBuf};
 use std::str::from_utf8;
-use std::sync::{Arc, RwLock};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
 use std::thread;
-use std::time::Duration;
+use std::time::{Duration, Instant};
 
 pub struct TcpServer {
     bind_addr: std::net::SocketAddr,
+    uds: Option<PathBuf>,
     data: Vec<u8>,
+    read_delay: Duration,
+    read_buffer: usize,
+    reuseport: bool,
+    auth_token: String,
+    echo: bool,
+    stats: Arc<IngestStats>,
+    // with_statsで共有した場合は呼び出し元が受信レートを出力する
+    shared_stats: bool,
+    drain_timeout: Duration,
+}
+
+/// 全てのリスナーで共有する受信バイト数と接続数
+#[derive(Default)]
+pub struct IngestStats {
+    received_bytes: AtomicUsize,
+    connection_count: AtomicUsize,
+}
+
+impl IngestStats {
+    /// 1秒毎に全てのリスナーの合計の受信レートと接続数を出力するスレッドを起動する
+    pub fn spawn_reporter(stats: Arc<IngestStats>) {
+        thread::spawn(move || loop {
+            thread::sleep(Duration::from_secs(1));
+            let bytes = stats.received_bytes.swap(0, Ordering::Relaxed);
+            info!(
+                "Ingest rate: {:.3} MB/s, connections: {}",
+                bytes as f64 / 1_000_000.0,
+                stats.connection_count.load(Ordering::Relaxed)
+            );
+        });
+    }
+}
+
+/// 接続毎の状態
+struct ConnectionState {
+    token: Token,
+    // 現在登録しているイベント、読み込みを途中で止めた場合に同じイベントで再登録する
+    interest: Interest,
+    peer: String,
+    accepted_at: Instant,
+    received_bytes: usize,
+    sent_bytes: usize,
+    authenticated: bool,
+    // 認証メッセージの改行を受信するまで、受信データを溜める
+    auth_buffer: Vec<u8>,
+    // エコーで送信しきれなかったデータ
+    pending: Vec<u8>,
 }
 
 impl TcpServer {
     const SERVER: Token = Token(0);
     const WAKER: Token = Token(1);
 
-    pub fn new(bind_addr_config: std::net::SocketAddr, packet_size_config: usize) -> TcpServer {
+    pub fn new(
+        bind_addr_config: std::net::SocketAddr,
+        packet_size_config: usize,
+        read_delay_config: u64,
+        read_buffer_config: usize,
+        reuseport_config: bool,
+        auth_token_config: String,
+    ) -> TcpServer {
+        // 0の場合は読み込めずに接続のクローズと区別できないため
+        assert!(read_buffer_config > 0, "read_buffer must be greater than 0");
         info!(
-            "config bind_addr: {}, packet_size: {}",
-            bind_addr_config, packet_size_config
+            "config bind_addr: {}, packet_size: {}, read_delay: {}ms, read_buffer: {}, reuseport: {}",
+            bind_addr_config,
+            packet_size_config,
+            read_delay_config,
+            read_buffer_config,
+            reuseport_config
         );
+        info!("config auth: {}", !auth_token_config.is_empty());
         TcpServer {
             bind_addr: bind_addr_config,
+            uds: None,
             data: vec![0x31; packet_size_config],
+            read_delay: Duration::from_millis(read_delay_config),
+            read_buffer: read_buffer_config,
+            reuseport: reuseport_config,
+            auth_token: auth_token_config,
+            echo: false,
+            stats: Arc::new(IngestStats::default()),
+            shared_stats: false,
+            drain_timeout: Duration::from_secs(0),
+        }
+    }
+
+    /// 受信したデータをそのまま送り返す、クライアントの "to echo server" と組み合わせて検証できる
+    /// エコーしない場合は、接続毎に送信データを一度だけ送信する
+    pub fn with_echo(mut self, echo_config: bool) -> TcpServer {
+        info!("config echo: {}", echo_config);
+        self.echo = echo_config;
+        self
+    }
+
+    /// 他のリスナーと受信バイト数と接続数を共有する
+    /// 共有した場合は受信レートを出力しないため、呼び出し元で IngestStats::spawn_reporter を起動する
+    pub fn with_stats(mut self, stats: Arc<IngestStats>) -> TcpServer {
+        self.stats = stats;
+        self.shared_stats = true;
+        self
+    }
+
+    /// 停止を要求されてから、既存の接続の終了を待つ時間、経過後は残りの接続を記録して閉じる
+    pub fn with_drain_timeout(mut self, drain_timeout_config: u64) -> TcpServer {
+        info!("config drain_timeout: {}s", drain_timeout_config);
+        self.drain_timeout = Duration::from_secs(drain_timeout_config);
+        self
+    }
+
+    /// 待ち受けるUnixドメインソケットのパス、空の場合はTCPで待ち受ける
+    pub fn with_uds(mut self, uds_config: &str) -> TcpServer {
+        info!("config uds: {}", uds_config);
+        if !uds_config.is_empty() {
+            self.uds = Some(PathBuf::from(uds_config));
         }
+        self
     }
 
     pub fn test_traffic_load(&self) -> io::Result<()> {
@@ -36,102 +151,243 @@ impl TcpServer {
         } else {
             info!("Send (none UTF-8) data: {:?}", tmp);
         }
+        // 共有していない場合は、このリスナーだけの受信レートを出力する
+        if !self.shared_stats {
+            IngestStats::spawn_reporter(Arc::clone(&self.stats));
+        }
         // pollのインスタンスを作成
         let mut poll = Poll::new()?;
         // eventのストレージ領域
         let mut events = Events::with_capacity(128);
 
         // サーバーソケットを設定
-        let mut server = TcpListener::bind(self.bind_addr)?;
+        let mut server = self.bind()?;
         // 着信接続のリスニングを開始
         poll.registry()
             .register(&mut server, Self::SERVER, Interest::READABLE)?;
+        // 停止を要求された場合はリスナーを閉じ、新しい接続を受け付けない
+        let mut listener = Some(server);
+        // 停止を要求された後、既存の接続の終了を待つ期限
+        let mut drain_deadline: Option<Instant> = None;
 
-        // `Token` -> `TcpStream` のマップ
+        // `Token` -> `Stream` のマップ
         let mut connections = HashMap::new();
+        // `Token` -> 接続毎の状態 のマップ
+        let mut states = HashMap::new();
         //  着信接続のユニークトークン
         let mut unique_token = Token(Self::WAKER.0 + 1);
 
+        // 受信バッファは全ての接続で使い回す
+        let mut received_data = vec![0; self.read_buffer];
+
         let waker = Arc::new(Waker::new(poll.registry(), Self::WAKER)?);
         let waker_clone = waker.clone();
-        let counter = Arc::new(RwLock::new(0));
+        shutdown::register(Arc::clone(&waker))?;
+        // 書き込みはwakerスレッドのみのため、ロックを使わずアトミックに数える
+        let counter = Arc::new(AtomicUsize::new(0));
         {
             let counter = Arc::clone(&counter);
 
             thread::spawn(move || {
-                while *counter.read().unwrap() < 100 {
+                while counter.load(Ordering::Relaxed) < 100 {
                     thread::sleep(Duration::from_nanos(1000));
-                    info!("wake {}", *counter.read().unwrap());
-                    {
-                        let mut w = counter.write().unwrap();
-                        *w += 1;
-                    }
+                    info!("wake {}", counter.fetch_add(1, Ordering::Relaxed));
                     waker_clone.wake().expect("unable to wake");
                 }
             });
         }
 
         loop {
-            // イベントが発生するまで待機 Poll Mio
-            poll.poll(&mut events, None)?;
+            // イベントが発生するまで待機 Poll Mio、停止中は期限までとする
+            let timeout =
+                drain_deadline.map(|deadline| deadline.saturating_duration_since(Instant::now()));
+            match poll.poll(&mut events, timeout) {
+                Ok(()) => {}
+                // シグナルで中断された場合は、停止の要求を確認してから再度待機する
+                Err(ref err) if self.interrupted(err) => {}
+                Err(err) => return Err(err),
+            }
 
             // 各イベントの処理
             for event in events.iter() {
                 // "register" に登録したトークンをを利用して、どのソケットのイベントか判断できる
                 match event.token() {
-                    Self::SERVER => loop {
-                        // サーバーのイベントの場合、接続準備ができていることを意味する
-                        // 接続を許可し、すぐにドロップする
-                        // これにより、ソケットがクローズされ、クライアントへEOFを通知
-                        let (mut connection, address) = match server.accept() {
-                            Ok((connection, address)) => (connection, address),
-                            Err(e) if e.kind() == io::ErrorKind::WouldBlock => {
-                                // `WouldBlock` エラーが発生した場合、リスナーは着信接続がキューにないことがわかるので、
-                                // ポーリングに戻り次の接続を待つ。
-                                break;
-                            }
-                            Err(e) => {
-                                // 他の種類のエラーの場合は、何かの誤りがあるため終了
-                                return Err(e);
-                            }
-                        };
+                    Self::SERVER => {
+                        while let Some(server) = listener.as_mut() {
+                            // サーバーのイベントの場合、接続準備ができていることを意味する
+                            // 接続を許可し、すぐにドロップする
+                            // これにより、ソケットがクローズされ、クライアントへEOFを通知
+                            let (mut connection, address) = match server.accept() {
+                                Ok((connection, address)) => (connection, address),
+                                Err(e) if e.kind() == io::ErrorKind::WouldBlock => {
+                                    // `WouldBlock` エラーが発生した場合、リスナーは着信接続がキューにないことがわかるので、
+                                    // ポーリングに戻り次の接続を待つ。
+                                    break;
+                                }
+                                Err(e) => {
+                                    // 他の種類のエラーの場合は、何かの誤りがあるため終了
+                                    return Err(e);
+                                }
+                            };
 
-                        info!("Accepted connection from: {}", address);
+                            info!("Accepted connection from: {}", address);
 
-                        let token = self.next(&mut unique_token);
-                        poll.registry().register(
-                            &mut connection,
-                            token,
-                            Interest::READABLE.add(Interest::WRITABLE),
-                        )?;
+                            let token = self.next(&mut unique_token);
+                            poll.registry().register(
+                                &mut connection,
+                                token,
+                                Interest::READABLE.add(Interest::WRITABLE),
+                            )?;
 
-                        connections.insert(token, connection);
-                    },
+                            connections.insert(token, connection);
+                            states.insert(
+                                token,
+                                ConnectionState {
+                                    token,
+                                    interest: Interest::READABLE.add(Interest::WRITABLE),
+                                    peer: address,
+                                    accepted_at: Instant::now(),
+                                    received_bytes: 0,
+                                    sent_bytes: 0,
+                                    authenticated: self.auth_token.is_empty(),
+                                    auth_buffer: Vec::new(),
+                                    pending: Vec::new(),
+                                },
+                            );
+                            self.stats.connection_count.fetch_add(1, Ordering::Relaxed);
+                        }
+                    }
                     Self::WAKER => {
                         // WAKER の場合は全ての接続へ送信
-                        for (_, mut connection) in connections {
-                            self.handle_connection_event(poll.registry(), &mut connection, event);
+                        let mut done_tokens = Vec::new();
+                        for (token, connection) in connections.iter_mut() {
+                            if let Some(state) = states.get_mut(token) {
+                                match self.handle_connection_event(
+                                    poll.registry(),
+                                    connection,
+                                    event,
+                                    &mut received_data,
+                                    state,
+                                ) {
+                                    Ok(false) => {}
+                                    Ok(true) => done_tokens.push(*token),
+                                    Err(err) => {
+                                        info!("Connection error: {}", err);
+                                        done_tokens.push(*token);
+                                    }
+                                }
+                            }
+                        }
+                        for token in done_tokens {
+                            self.close_connection(
+                                poll.registry(),
+                                token,
+                                &mut connections,
+                                &mut states,
+                            )?;
                         }
                     }
                     token => {
                         // TCP接続を受信した可能性がある
-                        let done = if let Some(connection) = connections.get_mut(&token) {
-                            self.handle_connection_event(poll.registry(), connection, event)?
+                        let done = if let (Some(connection), Some(state)) =
+                            (connections.get_mut(&token), states.get_mut(&token))
+                        {
+                            match self.handle_connection_event(
+                                poll.registry(),
+                                connection,
+                                event,
+                                &mut received_data,
+                                state,
+                            ) {
+                                Ok(done) => done,
+                                // エラーの場合も接続を記録してから破棄する
+                                Err(err) => {
+                                    info!("Connection error: {}", err);
+                                    true
+                                }
+                            }
                         } else {
                             // まばらなイベントが発生した場合は無視できる
                             false
                         };
                         if done {
-                            if let Some(mut connection) = connections.remove(&token) {
-                                poll.registry().deregister(&mut connection)?;
-                            }
+                            self.close_connection(
+                                poll.registry(),
+                                token,
+                                &mut connections,
+                                &mut states,
+                            )?;
                         }
                     }
                 }
             }
+
+            if drain_deadline.is_none() && shutdown::requested() {
+                if let Some(mut server) = listener.take() {
+                    poll.registry().deregister(&mut server)?;
+                }
+                info!("Draining {} connections", connections.len());
+                drain_deadline = Some(Instant::now() + self.drain_timeout);
+            }
+            if let Some(deadline) = drain_deadline {
+                if connections.is_empty() || Instant::now() >= deadline {
+                    // 期限までに終了しなかった接続も記録してから閉じる
+                    let tokens: Vec<Token> = connections.keys().copied().collect();
+                    info!("Closing {} connections", tokens.len());
+                    for token in tokens {
+                        self.close_connection(
+                            poll.registry(),
+                            token,
+                            &mut connections,
+                            &mut states,
+                        )?;
+                    }
+                    shutdown::unregister(&waker);
+                    info!("Tcp Server stopped");
+                    return Ok(());
+                }
+            }
         }
     }
 
+    /// `TcpListener::bind` と同様にリスナーを作成する
+    /// `reuseport` の場合は、同じアドレスに複数のリスナーをバインドできるようにする
+    fn bind(&self) -> io::Result<Listener> {
+        if let Some(path) = &self.uds {
+            return self.bind_uds(path);
+        }
+        let socket = if self.bind_addr.is_ipv4() {
+            TcpSocket::new_v4()?
+        } else {
+            TcpSocket::new_v6()?
+        };
+        socket.set_reuseaddr(true)?;
+        #[cfg(unix)]
+        socket.set_reuseport(self.reuseport)?;
+        socket.bind(self.bind_addr)?;
+        Ok(Listener::Tcp(socket.listen(1024)?))
+    }
+
+    /// Unixドメインソケットで待ち受ける
+    #[cfg(unix)]
+    fn bind_uds(&self, path: &Path) -> io::Result<Listener> {
+        // 前回のソケットファイルが残っている場合は削除、ソケット以外のファイルは残す
+        if let Ok(metadata) = fs::symlink_metadata(path) {
+            if metadata.file_type().is_socket() {
+                fs::remove_file(path)?;
+            }
+        }
+        Ok(Listener::Unix(UnixListener::bind(path)?))
+    }
+
+    #[cfg(not(unix))]
+    fn bind_uds(&self, _path: &Path) -> io::Result<Listener> {
+        Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            "Unix domain sockets are not supported on this platform",
+        ))
+    }
+
     fn next(&self, current: &mut Token) -> Token {
         let next = current.0;
         current.0 += 1;
@@ -139,19 +395,26 @@ impl TcpServer {
     }
 
     /// 接続が完了した場合、`true`を返す
+    /// 認証が必要な場合、認証されるまでは送信しない
     fn handle_connection_event(
         &self,
         registry: &Registry,
-        connection: &mut TcpStream,
+        connection: &mut Stream,
         event: &Event,
+        received_data: &mut [u8],
+        state: &mut ConnectionState,
     ) -> io::Result<bool> {
-        if event.is_writable() {
+        if event.is_writable() && state.authenticated && self.echo {
+            // 送信しきれなかったエコーの続きを送信
+            self.flush_echo(connection, state)?;
+        } else if event.is_writable() && state.authenticated {
             // 該当の接続へ書き込みできる可能性がある
             match connection.write(&self.data) {
                 // バッファへ`DATA`を一度に書き込む
                 // `DATA`より書き込めた長さが短い場合、書き込みエラーを返す
                 // `io::Write::write_all` と同様の動き
                 Ok(n) if n < self.data.len() => {
+                    state.sent_bytes += n;
                     let tmp = &self.data;
                     if let Ok(str_buf) = from_utf8(tmp) {
                         info!("Sent data: {}", str_buf.trim_end());
@@ -160,15 +423,22 @@ impl TcpServer {
                     }
                     return Err(io::ErrorKind::WriteZero.into());
                 }
-                Ok(_) => {
+                Ok(n) => {
+                    state.sent_bytes += n;
                     // 書き込み後は、受信イベントのみに反応するように接続を再登録
-                    registry.reregister(connection, event.token(), Interest::READABLE)?
+                    self.reregister(registry, connection, state, Interest::READABLE)?
                 }
                 // Would block errors はOSがこのI/Oのオペレーションを実行する準備ができていないことを表す
                 Err(ref err) if self.would_block(err) => {}
                 // 割り込みが入った場合やり直す
                 Err(ref err) if self.interrupted(err) => {
-                    return self.handle_connection_event(registry, connection, event)
+                    return self.handle_connection_event(
+                        registry,
+                        connection,
+                        event,
+                        received_data,
+                        state,
+                    )
                 }
                 // 他のエラーは致命的なエラーとして処理
                 Err(err) => return Err(err),
@@ -176,10 +446,14 @@ impl TcpServer {
         }
 
         if event.is_readable() {
+            // 遅いコンシューマーを模擬するため、読み込み前に待機
+            if !self.read_delay.is_zero() {
+                thread::sleep(self.read_delay);
+            }
             let mut connection_closed = false;
-            let mut received_data = vec![0; 4096];
             let mut bytes_read = 0;
             // 該当の接続から受信できる可能性がある
+            // 1回のイベントで読み込むのは read_buffer までとし、遅いコンシューマーを模擬する
             loop {
                 match connection.read(&mut received_data[bytes_read..]) {
                     Ok(0) => {
@@ -190,7 +464,10 @@ impl TcpServer {
                     Ok(n) => {
                         bytes_read += n;
                         if bytes_read == received_data.len() {
-                            received_data.resize(received_data.len() + 1024, 0);
+                            // エッジトリガーのため、再登録して残りのデータを次のイベントで読み込む
+                            let interest = state.interest;
+                            self.reregister(registry, connection, state, interest)?;
+                            break;
                         }
                     }
                     // Would block errors はOSがこのI/Oのオペレーションを実行する準備ができていないことを表す
@@ -202,11 +479,51 @@ impl TcpServer {
             }
 
             if bytes_read != 0 {
-                let received_data = &received_data[..bytes_read];
-                if let Ok(str_buf) = from_utf8(received_data) {
-                    info!("Received data: {}", str_buf.trim_end());
-                } else {
-                    info!("Received (none UTF-8) data: {:?}", received_data);
+                self.stats
+                    .received_bytes
+                    .fetch_add(bytes_read, Ordering::Relaxed);
+                state.received_bytes += bytes_read;
+                let mut received_data = &received_data[..bytes_read];
+                let auth_buffer;
+                if !state.authenticated {
+                    // 認証メッセージは複数のセグメントに分かれて届く場合があるため、改行まで溜めてから確認する
+                    state.auth_buffer.extend_from_slice(received_data);
+                    auth_buffer = std::mem::take(&mut state.auth_buffer);
+                    // 最初のメッセージが認証メッセージでなければ切断
+                    match auth::check_message(&self.auth_token, &auth_buffer) {
+                        auth::Verdict::Accepted(rest) => {
+                            info!("Authenticated connection");
+                            state.authenticated = true;
+                            received_data = rest;
+                            // 認証まで保留していた送信のため、書き込みイベントを再度受け取る
+                            self.reregister(
+                                registry,
+                                connection,
+                                state,
+                                Interest::READABLE.add(Interest::WRITABLE),
+                            )?;
+                        }
+                        auth::Verdict::Incomplete => {
+                            state.auth_buffer = auth_buffer;
+                            received_data = &[];
+                        }
+                        auth::Verdict::Rejected => {
+                            info!("Rejected unauthenticated connection");
+                            return Ok(true);
+                        }
+                    }
+                }
+                // 認証メッセージのみの場合は出力しない
+                if !received_data.is_empty() {
+                    if let Ok(str_buf) = from_utf8(received_data) {
+                        info!("Received data: {}", str_buf.trim_end());
+                    } else {
+                        info!("Received (none UTF-8) data: {:?}", received_data);
+                    }
+                    if self.echo {
+                        state.pending.extend_from_slice(received_data);
+                        self.flush_echo(connection, state)?;
+                    }
                 }
             }
 
@@ -219,6 +536,69 @@ impl TcpServer {
         Ok(false)
     }
 
+    /// エコーするデータを送信できるだけ送信し、残りは次の書き込みイベントで送信する
+    fn flush_echo(&self, connection: &mut Stream, state: &mut ConnectionState) -> io::Result<()> {
+        while !state.pending.is_empty() {
+            match connection.write(&state.pending) {
+                Ok(0) => return Err(io::ErrorKind::WriteZero.into()),
+                Ok(n) => {
+                    state.sent_bytes += n;
+                    state.pending.drain(..n);
+                }
+                Err(ref err) if self.would_block(err) => break,
+                Err(ref err) if self.interrupted(err) => continue,
+                Err(err) => return Err(err),
+            }
+        }
+        Ok(())
+    }
+
+    /// 接続を再登録する、エッジトリガーのため準備ができていれば再度通知される
+    fn reregister(
+        &self,
+        registry: &Registry,
+        connection: &mut Stream,
+        state: &mut ConnectionState,
+        interest: Interest,
+    ) -> io::Result<()> {
+        state.interest = interest;
+        registry.reregister(connection, state.token, interest)
+    }
+
+    /// 完了した接続を記録してから、登録を解除して破棄する
+    fn close_connection(
+        &self,
+        registry: &Registry,
+        token: Token,
+        connections: &mut HashMap<Token, Stream>,
+        states: &mut HashMap<Token, ConnectionState>,
+    ) -> io::Result<()> {
+        if let Some(state) = states.remove(&token) {
+            self.log_connection(&state);
+        }
+        if let Some(mut connection) = connections.remove(&token) {
+            registry.deregister(&mut connection)?;
+            self.stats.connection_count.fetch_sub(1, Ordering::Relaxed);
+        }
+        Ok(())
+    }
+
+    /// 接続の記録をJSON Linesで `app::connection` ロガーへ出力する
+    /// 出力先とローテーションは log4rs.yaml で設定する
+    fn log_connection(&self, state: &ConnectionState) {
+        info!(
+            target: "app::connection",
+            "{}",
+            json!({
+                "peer": state.peer,
+                "duration_ms": state.accepted_at.elapsed().as_millis() as u64,
+                "received_bytes": state.received_bytes,
+                "sent_bytes": state.sent_bytes,
+                "authenticated": state.authenticated,
+            })
+        );
+    }
+
     fn would_block(&self, err: &io::Error) -> bool {
         err.kind() == io::ErrorKind::WouldBlock
     }
@@ -0,0 +1,211 @@
+use log::info;
+use serde_json::json;
+use std::fs;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// サーバーのプロセスのCPU使用率、RSS、オープンしているFD数、accept キューのドロップ数の集計
+/// /proc から読み込むため、Linux以外では読み込めない値は出力しない
+pub struct Telemetry {
+    clock_ticks: f64,
+    last_cpu_ticks: Option<u64>,
+    last_sampled_at: Instant,
+    first_listen_drops: Option<u64>,
+    last_listen_drops: Option<u64>,
+    samples: usize,
+    cpu_percent_sum: f64,
+    cpu_percent_max: f64,
+    rss_max_kb: u64,
+    open_fds_max: usize,
+}
+
+impl Telemetry {
+    pub fn new() -> Telemetry {
+        let listen_drops = read_listen_drops();
+        Telemetry {
+            clock_ticks: clock_ticks(),
+            last_cpu_ticks: read_cpu_ticks(),
+            last_sampled_at: Instant::now(),
+            first_listen_drops: listen_drops,
+            last_listen_drops: listen_drops,
+            samples: 0,
+            cpu_percent_sum: 0.0,
+            cpu_percent_max: 0.0,
+            rss_max_kb: 0,
+            open_fds_max: 0,
+        }
+    }
+
+    /// 1秒毎にサンプリングして出力するスレッドを起動する
+    pub fn spawn_sampler(telemetry: Arc<Mutex<Telemetry>>) {
+        thread::spawn(move || loop {
+            thread::sleep(Duration::from_secs(1));
+            telemetry.lock().unwrap().sample();
+        });
+    }
+
+    fn sample(&mut self) {
+        let now = Instant::now();
+        let cpu_ticks = read_cpu_ticks();
+        // 前回のサンプルからの経過時間に対する、ユーザーとシステムのCPU時間の割合
+        let cpu_percent = match (self.last_cpu_ticks, cpu_ticks) {
+            (Some(last), Some(current)) => {
+                let secs = (now - self.last_sampled_at).as_secs_f64();
+                let cpu_secs = current.saturating_sub(last) as f64 / self.clock_ticks;
+                Some(cpu_secs / secs * 100.0)
+            }
+            _ => None,
+        };
+        self.last_cpu_ticks = cpu_ticks;
+        self.last_sampled_at = now;
+        let rss_kb = read_rss_kb();
+        let open_fds = read_open_fds();
+        let listen_drops = read_listen_drops();
+        if listen_drops.is_some() {
+            self.last_listen_drops = listen_drops;
+        }
+
+        self.samples += 1;
+        if let Some(cpu_percent) = cpu_percent {
+            self.cpu_percent_sum += cpu_percent;
+            self.cpu_percent_max = self.cpu_percent_max.max(cpu_percent);
+        }
+        self.rss_max_kb = self.rss_max_kb.max(rss_kb.unwrap_or(0));
+        self.open_fds_max = self.open_fds_max.max(open_fds.unwrap_or(0));
+        info!(
+            "Telemetry {}",
+            json!({
+                "cpu_percent": cpu_percent,
+                "rss_kb": rss_kb,
+                "open_fds": open_fds,
+                "accept_drops": self.accept_drops(),
+            })
+        );
+    }
+
+    /// サンプリングを始めてからの accept キューのドロップ数
+    /// ListenDrops はネットワーク名前空間全体の値のため、他のプロセスのドロップも含む
+    fn accept_drops(&self) -> Option<u64> {
+        match (self.first_listen_drops, self.last_listen_drops) {
+            (Some(first), Some(last)) => Some(last.saturating_sub(first)),
+            _ => None,
+        }
+    }
+
+    /// サーバーの終了時に、クライアントの結果と突き合わせるための集計を出力する
+    pub fn report(&self) {
+        let cpu_percent_avg = if self.samples == 0 {
+            0.0
+        } else {
+            self.cpu_percent_sum / self.samples as f64
+        };
+        info!(
+            "Telemetry report: {}",
+            json!({
+                "samples": self.samples,
+                "cpu_percent_avg": cpu_percent_avg,
+                "cpu_percent_max": self.cpu_percent_max,
+                "rss_max_kb": self.rss_max_kb,
+                "open_fds_max": self.open_fds_max,
+                "accept_drops": self.accept_drops(),
+            })
+        );
+    }
+}
+
+impl Default for Telemetry {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(unix)]
+fn clock_ticks() -> f64 {
+    // SAFETY: sysconf は引数の値を返すだけで、メモリを変更しない
+    let ticks = unsafe { libc::sysconf(libc::_SC_CLK_TCK) };
+    if ticks > 0 {
+        ticks as f64
+    } else {
+        100.0
+    }
+}
+
+#[cfg(not(unix))]
+fn clock_ticks() -> f64 {
+    100.0
+}
+
+fn read_cpu_ticks() -> Option<u64> {
+    parse_cpu_ticks(&fs::read_to_string("/proc/self/stat").ok()?)
+}
+
+fn read_rss_kb() -> Option<u64> {
+    parse_rss_kb(&fs::read_to_string("/proc/self/status").ok()?)
+}
+
+fn read_open_fds() -> Option<usize> {
+    // 一覧の読み込み自体に使うFDを除く
+    let count = fs::read_dir("/proc/self/fd").ok()?.count();
+    Some(count.saturating_sub(1))
+}
+
+fn read_listen_drops() -> Option<u64> {
+    parse_listen_drops(&fs::read_to_string("/proc/net/netstat").ok()?)
+}
+
+/// /proc/self/stat の utime と stime の合計
+/// コマンド名に空白や括弧を含む場合があるため、最後の ')' より後ろを数える
+fn parse_cpu_ticks(stat: &str) -> Option<u64> {
+    let fields: Vec<&str> = stat[stat.rfind(')')? + 1..].split_whitespace().collect();
+    // ')' の後ろは3番目の state から始まるため、14番目の utime は11となる
+    let utime: u64 = fields.get(11)?.parse().ok()?;
+    let stime: u64 = fields.get(12)?.parse().ok()?;
+    Some(utime + stime)
+}
+
+/// /proc/self/status の VmRSS (kB)
+fn parse_rss_kb(status: &str) -> Option<u64> {
+    let line = status.lines().find(|line| line.starts_with("VmRSS:"))?;
+    line.split_whitespace().nth(1)?.parse().ok()
+}
+
+/// /proc/net/netstat の TcpExt の ListenDrops
+/// 項目名の行と値の行が交互に並ぶ
+fn parse_listen_drops(netstat: &str) -> Option<u64> {
+    let mut lines = netstat.lines().filter(|line| line.starts_with("TcpExt:"));
+    let names = lines.next()?.split_whitespace();
+    let values = lines.next()?.split_whitespace();
+    let (_, value) = names.zip(values).find(|(name, _)| *name == "ListenDrops")?;
+    value.parse().ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_cpu_ticks_sums_utime_and_stime() {
+        let stat =
+            "1234 (nelst (x)) S 1 1234 1234 0 -1 4194560 100 0 0 0 25 17 0 0 20 0 3 0 100 0 0";
+        assert_eq!(parse_cpu_ticks(stat), Some(42));
+        assert_eq!(parse_cpu_ticks("1234 (nelst) S 1"), None);
+    }
+
+    #[test]
+    fn parse_rss_kb_reads_vmrss() {
+        let status = "Name:\tnelst\nVmPeak:\t  20000 kB\nVmRSS:\t    5120 kB\nThreads:\t3\n";
+        assert_eq!(parse_rss_kb(status), Some(5120));
+        assert_eq!(parse_rss_kb("Name:\tnelst\n"), None);
+    }
+
+    #[test]
+    fn parse_listen_drops_reads_tcpext_column() {
+        let netstat = "TcpExt: SyncookiesSent ListenOverflows ListenDrops\n\
+                       TcpExt: 0 3 7\n\
+                       IpExt: InNoRoutes\n\
+                       IpExt: 0\n";
+        assert_eq!(parse_listen_drops(netstat), Some(7));
+        assert_eq!(parse_listen_drops("IpExt: InNoRoutes\nIpExt: 0\n"), None);
+    }
+}
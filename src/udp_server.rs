@@ -0,0 +1,177 @@
+use crate::auth;
+use crate::shutdown;
+use log::{debug, info};
+use mio::net::UdpSocket;
+use mio::{Events, Interest, Poll, Token, Waker};
+use std::collections::HashMap;
+use std::io;
+use std::net::SocketAddr;
+use std::str::from_utf8;
+use std::sync::Arc;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+pub struct UdpServer {
+    bind_addr: std::net::SocketAddr,
+    timestamps: bool,
+    auth_token: String,
+}
+
+impl UdpServer {
+    const SERVER: Token = Token(0);
+    // 停止を要求された場合に起こされる
+    const SHUTDOWN: Token = Token(1);
+    // UDPデータグラムの最大サイズ
+    const MAX_DATAGRAM_SIZE: usize = 65536;
+    // 送信できるUDPのペイロードの最大サイズ (IPv4)
+    const MAX_PAYLOAD_SIZE: usize = 65507;
+    // timestamps で付与する受信時刻と送信時刻のサイズ
+    const TIMESTAMPS_SIZE: usize = 16;
+
+    pub fn new(
+        bind_addr_config: std::net::SocketAddr,
+        timestamps_config: bool,
+        auth_token_config: String,
+    ) -> UdpServer {
+        info!(
+            "config bind_addr: {}, timestamps: {}, auth: {}",
+            bind_addr_config,
+            timestamps_config,
+            !auth_token_config.is_empty()
+        );
+        UdpServer {
+            bind_addr: bind_addr_config,
+            timestamps: timestamps_config,
+            auth_token: auth_token_config,
+        }
+    }
+
+    pub fn test_traffic_load(&self) -> io::Result<()> {
+        // pollのインスタンスを作成
+        let mut poll = Poll::new()?;
+        // eventのストレージ領域
+        let mut events = Events::with_capacity(128);
+
+        // サーバーソケットを設定
+        let mut socket = UdpSocket::bind(self.bind_addr)?;
+        poll.registry()
+            .register(&mut socket, Self::SERVER, Interest::READABLE)?;
+
+        // 送信元 -> (受信パケット数, 受信バイト数) のマップ
+        let mut peers: HashMap<SocketAddr, (usize, usize)> = HashMap::new();
+        let mut received_data = vec![0; Self::MAX_DATAGRAM_SIZE];
+
+        let waker = Arc::new(Waker::new(poll.registry(), Self::SHUTDOWN)?);
+        shutdown::register(Arc::clone(&waker))?;
+
+        loop {
+            // イベントが発生するまで待機 Poll Mio
+            match poll.poll(&mut events, None) {
+                Ok(()) => {}
+                // シグナルで中断された場合は、停止の要求を確認してから再度待機する
+                Err(ref err) if self.interrupted(err) => {}
+                Err(err) => return Err(err),
+            }
+
+            // UDPは接続がないため、停止を要求された場合は送信元毎の集計を出力してすぐに終了する
+            if shutdown::requested() {
+                for (peer, (packets, bytes)) in &peers {
+                    info!("Peer {}: packets {}, bytes {}", peer, packets, bytes);
+                }
+                shutdown::unregister(&waker);
+                info!("Udp Server stopped");
+                return Ok(());
+            }
+
+            for event in events.iter() {
+                match event.token() {
+                    Self::SERVER => loop {
+                        // 受信できるデータグラムがなくなるまで読み込む
+                        let (bytes_read, peer) = match socket.recv_from(&mut received_data) {
+                            Ok((bytes_read, peer)) => (bytes_read, peer),
+                            // Would block errors はOSがこのI/Oのオペレーションを実行する準備ができていないことを表す
+                            Err(ref err) if self.would_block(err) => break,
+                            Err(ref err) if self.interrupted(err) => continue,
+                            // 他のエラーは致命的なエラーとして処理
+                            Err(err) => return Err(err),
+                        };
+
+                        let received_at = Self::now_nanos();
+                        let mut received_data = &received_data[..bytes_read];
+                        // 認証が必要な場合、認証メッセージで始まらないデータグラムは応答せずに破棄
+                        if !self.auth_token.is_empty() {
+                            match auth::strip_message(&self.auth_token, received_data) {
+                                Some(rest) => received_data = rest,
+                                None => {
+                                    info!("Rejected unauthenticated datagram from {}", peer);
+                                    continue;
+                                }
+                            }
+                        }
+                        if let Ok(str_buf) = from_utf8(received_data) {
+                            info!("Received data from {}: {}", peer, str_buf.trim_end());
+                        } else {
+                            info!(
+                                "Received (none UTF-8) data from {}: {:?}",
+                                peer, received_data
+                            );
+                        }
+
+                        let stats = peers.entry(peer).or_insert((0, 0));
+                        stats.0 += 1;
+                        stats.1 += bytes_read;
+                        debug!("Peer {}: packets {}, bytes {}", peer, stats.0, stats.1);
+
+                        // 受信したデータグラムを送信元へエコー
+                        // timestamps の場合は受信時刻と送信時刻を末尾に付与し、上りと下りの遅延を分けられるようにする
+                        // 時刻を付与すると送信できるサイズを超える場合は、応答せずに破棄
+                        if self.timestamps
+                            && received_data.len() + Self::TIMESTAMPS_SIZE > Self::MAX_PAYLOAD_SIZE
+                        {
+                            info!(
+                                "Dropped echo to {}: {} bytes with timestamps exceeds {} bytes",
+                                peer,
+                                received_data.len() + Self::TIMESTAMPS_SIZE,
+                                Self::MAX_PAYLOAD_SIZE
+                            );
+                            continue;
+                        }
+                        let sent = if self.timestamps {
+                            let mut reply = received_data.to_vec();
+                            reply.extend_from_slice(&received_at.to_be_bytes());
+                            reply.extend_from_slice(&Self::now_nanos().to_be_bytes());
+                            socket.send_to(&reply, peer)
+                        } else {
+                            socket.send_to(received_data, peer)
+                        };
+                        match sent {
+                            Ok(_) => {}
+                            // 送信バッファが一杯の場合は、UDPのため破棄する
+                            Err(ref err) if self.would_block(err) => {
+                                debug!("Dropped echo to {}", peer);
+                            }
+                            // 他のエラーもデータグラム毎のため、破棄して次のデータグラムを処理
+                            Err(err) => info!("Dropped echo to {}: {}", peer, err),
+                        }
+                    },
+                    Self::SHUTDOWN => {}
+                    _ => unreachable!(),
+                }
+            }
+        }
+    }
+
+    /// UNIXエポックからのナノ秒
+    fn now_nanos() -> u64 {
+        SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |elapsed| elapsed.as_nanos() as u64)
+    }
+
+    fn would_block(&self, err: &io::Error) -> bool {
+        err.kind() == io::ErrorKind::WouldBlock
+    }
+
+    fn interrupted(&self, err: &io::Error) -> bool {
+        err.kind() == io::ErrorKind::Interrupted
+    }
+}